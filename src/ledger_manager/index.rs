@@ -1,11 +1,27 @@
 use crate::crypto::hash::H256;
 use std::sync::{Arc, Weak};
 use std::sync::Mutex;
-use std::convert::TryFrom;
 use crate::chain::*;
 use std::collections::{HashMap, HashSet};
 use std::iter::{IntoIterator, FromIterator};
+use std::fmt;
 use statrs::distribution::{Discrete, Poisson, Univariate};
+use serde::{Deserialize, Serialize};
+
+// confirmation policy parameters from https://arxiv.org/abs/1810.08092
+const DEFAULT_QUANTILE: f32 = 3.09; // ~0.999 one-sided confidence
+const DEFAULT_ADVERSARY_RATIO: f32 = 0.3;
+
+/// One level's confirmation status changing as a result of
+/// `advance_ledger_to`, in the order it happened, so a caller (e.g. the
+/// demo visualizer's `leader_elected`/`level_deconfirmed` sinks) can
+/// attribute each transaction addition or removal to the level that
+/// caused it instead of only seeing a flattened, level-less list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelEvent {
+    Elected { level: usize, leader: H256, added: Vec<H256> },
+    Deconfirmed { level: usize, removed: Vec<H256> },
+}
 
 pub struct LedgerIndex {
     voter_tips: Vec<Arc<Voter>>,
@@ -13,68 +29,373 @@ pub struct LedgerIndex {
     unconfirmed_proposer: HashSet<H256>,
     leader_sequence: Vec<Option<H256>>,
     ledger_order: Vec<Option<Vec<H256>>>,
+    // proposer blocks known to this index, indexed by hash, so that a
+    // newly confirmed leader's proposer_refs/transaction_refs can be
+    // walked without needing a separate block database lookup.
+    proposer_blocks: HashMap<H256, Arc<Proposer>>,
+    // every transaction block hash currently in the confirmed ledger
+    // (rollback removes them again), so that re-expansion can tell which
+    // blocks are already accounted for.
+    confirmed_transactions: HashSet<H256>,
+    consensus: ConsensusParams,
+    // opt-in BFT finality over a prefix of leader_sequence; None means
+    // this deployment runs on probabilistic confirmation alone.
+    finality: Option<Finality>,
+    // cryptographic accumulator over the confirmed ledger, so a light
+    // client holding only `ledger_root()` can verify inclusion.
+    ledger_trie: MerkleTrie,
+    next_ledger_position: u64,
 }
 
 impl LedgerIndex {
-    // TODO: for now, we only have the ability to start from scratch
+    // builds the index from the given confirmed state; pass empty
+    // `leader_sequence`/`ledger_order` to start from scratch, i.e. with no
+    // leaders confirmed yet. to bootstrap from a persisted snapshot
+    // instead, use `from_snapshot`.
+    //
+    // takes `&[Arc<Voter>]` rather than `&[Voter]`: `voter_tips` is kept
+    // around as long-lived state (see the field of the same name above),
+    // so borrowing the `Arc`s the caller already holds avoids cloning
+    // every voter block just to store a tip reference.
     pub fn new<'a, T>(proposer_tip: &Arc<Proposer>, voter_tips: &[Arc<Voter>], unconfirmed: T,
                       leader_sequence: &[Option<H256>], ledger_order: &[Option<Vec<H256>>]) -> Self
     where T: IntoIterator<Item = &'a H256>,
     {
+        let (ledger_trie, confirmed_transactions, next_ledger_position) =
+            rebuild_ledger_trie(ledger_order);
         Self {
             voter_tips: voter_tips.to_vec(),
             proposer_tip: Arc::clone(&proposer_tip),
             unconfirmed_proposer: HashSet::from_iter(unconfirmed.into_iter().copied()),
             leader_sequence: leader_sequence.to_vec(),
             ledger_order: ledger_order.to_vec(),
+            proposer_blocks: HashMap::new(),
+            confirmed_transactions,
+            consensus: ConsensusParams::default(),
+            finality: None,
+            ledger_trie,
+            next_ledger_position,
         }
     }
 
+    pub fn ledger_root(&self) -> H256 {
+        self.ledger_trie.root()
+    }
+
+    pub fn inclusion_proof(&self, tx: H256) -> Option<MerkleProof> {
+        self.ledger_trie.inclusion_proof(tx)
+    }
+
     pub fn insert_unconfirmed(&mut self, hash: H256) {
         self.unconfirmed_proposer.insert(hash);
     }
 
-    // returns added transaction blocks, removed transaction blocks
-    //pub fn advance_ledger_to(&mut self, new_voter_tips: &[Voter]) -> (Vec<H256>, Vec<H256>) {}
-    //}
-    
-    fn proposer_leader(&self, voter_tips: &[Voter], level: u64, quantile: f32, adversary_ratio: f32) -> Option<H256> {
+    /// Switch the confirmation policy, e.g. to benchmark stake-weighted
+    /// confirmation against uniform per-chain confirmation over the same
+    /// block DAG.
+    pub fn set_consensus(&mut self, consensus: ConsensusParams) {
+        self.consensus = consensus;
+    }
+
+    /// Opt in to BFT finality: once more than 2/3 of `config`'s authority
+    /// weight precommits the same leader-prefix digest, that prefix can
+    /// never again be rolled back by `advance_ledger_to`.
+    pub fn enable_finality(&mut self, config: FinalityConfig) {
+        self.finality = Some(Finality::new(config));
+    }
+
+    pub fn finalized_height(&self) -> usize {
+        self.finality.as_ref().map_or(0, Finality::finalized_height)
+    }
+
+    /// The digest authorities should precommit to finalize the ledger
+    /// prefix up to (and including) `level`. Returns `None` if `level` (or
+    /// any level below it) isn't confirmed on this node yet, e.g. because
+    /// of a transient race between the networking layer and
+    /// `advance_ledger_to`; a caller driving the precommit protocol should
+    /// treat that as "not ready to precommit yet", not as a fatal error.
+    pub fn leader_prefix_digest(&self, level: usize) -> Option<H256> {
+        leader_prefix_digest(&self.leader_sequence, level + 1)
+    }
+
+    /// Record a precommit that the caller has already authenticated
+    /// against `authority`'s public key. Returns `true` if this
+    /// precommit newly finalized `level`.
+    ///
+    /// Rejects the precommit outright if `digest` doesn't match this
+    /// node's own `leader_prefix_digest(level)`: an honest authority only
+    /// ever precommits the digest of the chain it actually confirmed, so
+    /// a mismatch here means this node is briefly behind or on a
+    /// different fork from the authority set, and must not let it
+    /// permanently finalize a prefix it hasn't really confirmed.
+    pub fn record_finality_precommit(
+        &mut self,
+        round: u64,
+        level: usize,
+        authority: AuthorityId,
+        digest: H256,
+    ) -> bool {
+        match self.leader_prefix_digest(level) {
+            Some(expected) if expected == digest => {}
+            Some(_) => {
+                log::error!(
+                    "rejecting precommit for level {} from authority {:?}: digest doesn't match this node's own confirmed leader prefix",
+                    level, authority
+                );
+                return false;
+            }
+            None => {
+                log::warn!(
+                    "rejecting precommit for level {} from authority {:?}: not yet confirmed on this node",
+                    level, authority
+                );
+                return false;
+            }
+        }
+        match &mut self.finality {
+            Some(finality) => finality.record_precommit(round, level, authority, digest),
+            None => false,
+        }
+    }
+
+    // register a proposer block so that, once it (or a block referencing
+    // it) is elected leader, its refs can be walked to expand the ledger.
+    pub fn insert_proposer(&mut self, block: Arc<Proposer>) {
+        self.proposer_blocks.insert(block.hash, block);
+    }
+
+    /// Advance the confirmed ledger to reflect `new_voter_tips`.
+    ///
+    /// Re-runs the leader election test level by level, starting from
+    /// level 0, so that a level whose leader would change under the new
+    /// votes (including a previously confirmed leader being displaced, or
+    /// a confirmed leader losing confirmation entirely) is detected and
+    /// rolled back before the sequence is re-derived. Returns, in level
+    /// order, the levels that actually changed — see `LevelEvent`.
+    ///
+    /// `leader_sequence`/`ledger_order` are plain `Vec`s indexed by level,
+    /// so unwinding one level structurally requires unwinding everything
+    /// above it too; `plan_cascade` (unit-tested separately, since it
+    /// needs no real block DAG) then decides which of those unwound
+    /// levels actually need to be reported and re-derived versus which
+    /// simply get restored unchanged, so that a level whose own leader
+    /// didn't change never shows up as deconfirmed-then-reconfirmed
+    /// churn in the returned events.
+    pub fn advance_ledger_to(&mut self, new_voter_tips: &[Arc<Voter>]) -> Vec<LevelEvent> {
+        self.voter_tips = new_voter_tips.to_vec();
+        let voters: Vec<Voter> = self.voter_tips.iter().map(|v| (**v).clone()).collect();
+
+        let mut events: Vec<LevelEvent> = Vec::new();
+
+        let mut level: usize = 0;
+        loop {
+            let new_leader = self.proposer_leader(&voters, level as u64);
+            let prior_leader = self.leader_sequence.get(level).copied().flatten();
+
+            if new_leader == prior_leader {
+                if new_leader.is_none() {
+                    // this level, and everything above it, is still
+                    // unconfirmed; nothing further can be confirmed yet.
+                    break;
+                }
+                level += 1;
+                continue;
+            }
+
+            let finalized_height = self.finality.as_ref().map_or(0, Finality::finalized_height);
+            if level < finalized_height {
+                // an honest quorum already finalized this level; a
+                // differing result here means either a finality fault or
+                // a stale/adversarial vote set, never a legitimate reorg.
+                log::error!(
+                    "consensus fault: level {} would deconfirm below the finalized height {}, ignoring",
+                    level, finalized_height
+                );
+                level += 1;
+                continue;
+            }
+
+            if level == self.leader_sequence.len() {
+                // a level past the current tip has nothing to unwind; it
+                // differs from its prior (None) only by newly confirming.
+                if let Some(leader) = new_leader {
+                    let added = self.confirm_level(level, leader);
+                    events.push(LevelEvent::Elected { level, leader, added });
+                }
+                level += 1;
+                continue;
+            }
+
+            let unwound = self.unwind_from(level);
+            let steps = plan_cascade(unwound, level, |lvl| self.proposer_leader(&voters, lvl as u64));
+            let cascade_ended = matches!(steps.last(), Some(CascadeStep::Gone { .. }));
+            for step in steps {
+                self.apply_cascade_step(step, &mut events);
+            }
+            level = self.leader_sequence.len();
+            if cascade_ended {
+                break;
+            }
+        }
+
+        events
+    }
+
+    // pop every currently-confirmed level from `from_level` to the
+    // current tip (the only direction a Vec can be truncated from),
+    // returning what each one had confirmed in level order so the caller
+    // can decide, per level, whether to restore it unchanged or treat it
+    // as genuinely displaced.
+    fn unwind_from(&mut self, from_level: usize) -> Vec<(Option<H256>, Option<Vec<H256>>)> {
+        let mut unwound = Vec::new();
+        while self.leader_sequence.len() > from_level {
+            let order = self.ledger_order.pop().flatten();
+            let leader = self.leader_sequence.pop().flatten();
+            if let Some(txs) = &order {
+                for &tx in txs {
+                    self.confirmed_transactions.remove(&tx);
+                    self.ledger_trie.remove(tx);
+                }
+            }
+            if let Some(leader_hash) = leader {
+                self.unconfirmed_proposer.insert(leader_hash);
+            }
+            unwound.push((leader, order));
+        }
+        unwound.reverse();
+        unwound
+    }
+
+    fn apply_cascade_step(&mut self, step: CascadeStep, events: &mut Vec<LevelEvent>) {
+        match step {
+            CascadeStep::Unchanged { leader, order } => {
+                self.reconfirm_level(leader, order);
+            }
+            CascadeStep::Changed { level, leader, old_order } => {
+                if let Some(txs) = old_order {
+                    events.push(LevelEvent::Deconfirmed { level, removed: txs });
+                }
+                let added = self.confirm_level(level, leader);
+                events.push(LevelEvent::Elected { level, leader, added });
+            }
+            CascadeStep::Gone { level, old_order } => {
+                if let Some(txs) = old_order {
+                    events.push(LevelEvent::Deconfirmed { level, removed: txs });
+                }
+            }
+        }
+    }
+
+    // restore a level's previously confirmed leader/order exactly as it
+    // was: used when `plan_cascade` determines that, despite having to be
+    // popped off the tail along with a genuinely displaced level below
+    // it, this level's own leader didn't actually change.
+    fn reconfirm_level(&mut self, leader: Option<H256>, order: Option<Vec<H256>>) {
+        if let Some(leader_hash) = leader {
+            self.unconfirmed_proposer.remove(&leader_hash);
+        }
+        if let Some(txs) = &order {
+            for &tx in txs {
+                self.confirmed_transactions.insert(tx);
+                self.ledger_trie.insert(tx, self.next_ledger_position);
+                self.next_ledger_position += 1;
+            }
+        }
+        self.leader_sequence.push(leader);
+        self.ledger_order.push(order);
+    }
+
+    // confirm `leader` as the leader of `level`, expanding its ledger
+    // contribution and appending it to leader_sequence/ledger_order.
+    // Returns the transaction blocks this newly added to the ledger.
+    fn confirm_level(&mut self, level: usize, leader: H256) -> Vec<H256> {
+        debug_assert_eq!(level, self.leader_sequence.len());
+        self.unconfirmed_proposer.remove(&leader);
+
+        let mut visited_blocks = HashSet::new();
+        let mut seen_txs = HashSet::new();
+        let mut new_txs = Vec::new();
+        self.collect_new_transactions(leader, &mut visited_blocks, &mut seen_txs, &mut new_txs);
+
+        for tx in &new_txs {
+            self.confirmed_transactions.insert(*tx);
+            self.ledger_trie.insert(*tx, self.next_ledger_position);
+            self.next_ledger_position += 1;
+        }
+        self.leader_sequence.push(Some(leader));
+        self.ledger_order.push(Some(new_txs.clone()));
+        new_txs
+    }
+
+    // deterministically walk `block`'s proposer_refs (reference order,
+    // recursing depth-first so each ref's own refs are flushed first,
+    // i.e. a topological order), then its own transaction_refs, skipping
+    // any transaction block already in the ledger or already collected in
+    // this expansion.
+    fn collect_new_transactions(
+        &self,
+        block: H256,
+        visited_blocks: &mut HashSet<H256>,
+        seen_txs: &mut HashSet<H256>,
+        out: &mut Vec<H256>,
+    ) {
+        if !visited_blocks.insert(block) {
+            return;
+        }
+        let proposer = match self.proposer_blocks.get(&block) {
+            Some(p) => Arc::clone(p),
+            None => return,
+        };
+        for r in proposer.proposer_refs.iter() {
+            self.collect_new_transactions(r.hash, visited_blocks, seen_txs, out);
+        }
+        for tx in proposer.transaction_refs.iter() {
+            if self.confirmed_transactions.contains(tx) || !seen_txs.insert(*tx) {
+                continue;
+            }
+            out.push(*tx);
+        }
+    }
+
+    fn proposer_leader(&self, voter_tips: &[Voter], level: u64) -> Option<H256> {
         // compute the new leader of this level
-        // we use the confirmation policy from https://arxiv.org/abs/1810.08092
+        // we use the confirmation policy from https://arxiv.org/abs/1810.08092,
+        // generalized to weighted votes: in uniform mode every chain's
+        // weight is 1.0, reproducing the original per-chain vote count.
+        let quantile = self.consensus.quantile;
+        let adversary_ratio = self.consensus.adversary_ratio;
         let mut new_leader: Option<H256> = None;
 
-        // collect the depth of each vote on each proposer block
-        let mut votes_depth: HashMap<H256, Vec<u64>> = HashMap::new(); // chain number and vote depth cast on the proposer block
+        // collect the (depth, weight) of each vote on each proposer block
+        let mut votes_depth: HashMap<H256, Vec<(u64, f32)>> = HashMap::new();
 
-        // collect the total votes on all proposer blocks of the level, and the number of
-        // voter blocks mined on the main chain after those votes are casted
-        let mut total_vote_count: u16 = 0;
-        let mut total_vote_blocks: u64 = 0;
+        // collect the total vote weight on all proposer blocks of the level,
+        // and the (weighted) number of voter blocks mined on the main chain
+        // after those votes were cast
+        let mut total_vote_weight: f32 = 0.0;
+        let mut total_vote_blocks: f32 = 0.0;
 
         // get the vote from each voter chain
         for voter in voter_tips.iter() {
             let vote = voter.proposer_vote_of_level(level);
             // if this chain voted
             if let Some((hash, depth)) = vote {
-                if let Some(l) = votes_depth.get_mut(&hash) {
-                    l.push(depth);
-                } else {
-                    votes_depth.insert(hash, vec![depth]);
-                }
-                total_vote_count += 1;
+                let weight = self.consensus.chain_weight(voter.chain_number);
+                votes_depth.entry(hash).or_insert_with(Vec::new).push((depth, weight));
+                total_vote_weight += weight;
                 // count the number of blocks on main chain starting at the vote
-                total_vote_blocks += depth;
+                total_vote_blocks += weight * depth as f32;
             }
         }
         let proposer_blocks: Vec<H256> = votes_depth.keys().copied().collect();
-        let num_voter_chains = u16::try_from(voter_tips.len()).unwrap();
+        let total_weight = self.consensus.total_weight(voter_tips);
 
-        // no point in going further if less than 3/5 votes are cast
-        if total_vote_count > num_voter_chains * 3 / 5 {
+        // no point in going further if less than 3/5 of the weight voted
+        if total_vote_weight > total_weight * 3.0 / 5.0 {
             // calculate the average number of voter blocks mined after
             // a vote is casted. we use this as an estimator of honest mining
             // rate, and then derive the believed malicious mining rate
-            let avg_vote_blocks = total_vote_blocks as f32 / f32::from(total_vote_count);
+            let avg_vote_blocks = total_vote_blocks / total_vote_weight;
             // expected voter depth of an adversary
             let adversary_expected_vote_depth =
                 avg_vote_blocks / (1.0 - adversary_ratio) * adversary_ratio;
@@ -91,7 +412,7 @@ impl LedgerIndex {
                 let mut block_votes_mean: f32 = 0.0; // mean E[X]
                 let mut block_votes_variance: f32 = 0.0; // Var[X]
                 let mut block_votes_lcb: f32 = 0.0;
-                for depth in votes.iter() {
+                for (depth, weight) in votes.iter() {
                     // probability that the adversary will remove this vote
                     let mut p: f32 = 1.0 - poisson.cdf((*depth as f32 + 1.0).into()) as f32;
                     for k in 0..(*depth as u64) {
@@ -103,8 +424,10 @@ impl LedgerIndex {
                             .powi((depth - k + 1) as i32);
                         p += p1 * p2;
                     }
-                    block_votes_mean += 1.0 - p;
-                    block_votes_variance += p * (1.0 - p);
+                    // a weighted vote is a Bernoulli indicator scaled by its
+                    // weight: mean scales linearly, variance quadratically.
+                    block_votes_mean += weight * (1.0 - p);
+                    block_votes_variance += weight * weight * p * (1.0 - p);
                 }
                 // using gaussian approximation
                 let tmp = block_votes_mean - (block_votes_variance).sqrt() * quantile;
@@ -129,7 +452,7 @@ impl LedgerIndex {
                 }
             }
             // check if the lcb_vote of new_leader is bigger than second best ucb votes
-            let remaining_votes = f32::from(num_voter_chains) - total_votes_lcb;
+            let remaining_votes = total_weight - total_votes_lcb;
 
             // if max_vote_lcb is lesser than the remaining_votes, then a private block could
             // get the remaining votes and become the leader block
@@ -157,4 +480,1026 @@ impl LedgerIndex {
         }
         new_leader
     }
+
+    /// Snapshot the confirmed state of this index so it can be persisted
+    /// and later restored without replaying the block DAG from genesis.
+    pub fn to_snapshot(&self) -> LedgerIndexSnapshot {
+        LedgerIndexSnapshot {
+            version: SNAPSHOT_VERSION,
+            voter_tips: self.voter_tips.iter().map(|v| v.hash).collect(),
+            proposer_tip: self.proposer_tip.hash,
+            unconfirmed_proposer: self.unconfirmed_proposer.iter().copied().collect(),
+            leader_sequence: self.leader_sequence.clone(),
+            ledger_order: self.ledger_order.clone(),
+            finalized_height: self.finalized_height(),
+        }
+    }
+
+    /// Restore a `LedgerIndex` from a snapshot. The caller must already
+    /// have the voter tip and proposer tip blocks loaded (e.g. from a
+    /// block database keyed by hash); this only validates that they
+    /// match the hashes recorded in the snapshot. `finality_config` must
+    /// be `Some` if the snapshot was taken with finality enabled (i.e.
+    /// `finalized_height` is nonzero); otherwise the restored index would
+    /// silently lose its finality floor.
+    pub fn from_snapshot(
+        snapshot: &LedgerIndexSnapshot,
+        proposer_tip: &Arc<Proposer>,
+        voter_tips: &[Arc<Voter>],
+        finality_config: Option<FinalityConfig>,
+    ) -> Result<Self, SnapshotError> {
+        validate_snapshot(snapshot)?;
+        if proposer_tip.hash != snapshot.proposer_tip {
+            return Err(SnapshotError::Inconsistent("proposer tip does not match snapshot"));
+        }
+        if voter_tips.iter().map(|v| v.hash).ne(snapshot.voter_tips.iter().copied()) {
+            return Err(SnapshotError::Inconsistent("voter tips do not match snapshot"));
+        }
+        if snapshot.finalized_height > 0 && finality_config.is_none() {
+            return Err(SnapshotError::Inconsistent(
+                "snapshot has a finalized height but no finality config was supplied",
+            ));
+        }
+
+        let (ledger_trie, confirmed_transactions, next_ledger_position) =
+            rebuild_ledger_trie(&snapshot.ledger_order);
+
+        Ok(Self {
+            voter_tips: voter_tips.to_vec(),
+            proposer_tip: Arc::clone(proposer_tip),
+            unconfirmed_proposer: HashSet::from_iter(snapshot.unconfirmed_proposer.iter().copied()),
+            leader_sequence: snapshot.leader_sequence.clone(),
+            ledger_order: snapshot.ledger_order.clone(),
+            proposer_blocks: HashMap::new(),
+            confirmed_transactions,
+            consensus: ConsensusParams::default(),
+            finality: finality_config.map(|config| Finality::restore(config, snapshot.finalized_height)),
+            ledger_trie,
+            next_ledger_position,
+        })
+    }
+}
+
+// rebuild the confirmed-transaction set and Merkle trie implied by a
+// snapshot's `ledger_order`, inserting each transaction at the same
+// position it would have been assigned had it been confirmed
+// incrementally. Split out of `from_snapshot` so this position
+// bookkeeping — the part of the round trip most likely to regress
+// silently — has unit coverage independent of needing a real
+// `Proposer`/`Voter` pair to call `from_snapshot` itself.
+fn rebuild_ledger_trie(ledger_order: &[Option<Vec<H256>>]) -> (MerkleTrie, HashSet<H256>, u64) {
+    let mut trie = MerkleTrie::new();
+    let mut confirmed = HashSet::new();
+    let mut position = 0u64;
+    for tx in ledger_order.iter().flatten().flatten().copied() {
+        trie.insert(tx, position);
+        confirmed.insert(tx);
+        position += 1;
+    }
+    (trie, confirmed, position)
+}
+
+// what `advance_ledger_to` decided to do with one previously-confirmed
+// level after unwinding it off the tail of leader_sequence/ledger_order.
+#[derive(Debug, PartialEq)]
+enum CascadeStep {
+    /// This level's leader recomputed to the same hash it already had;
+    /// restore it exactly as it was rather than reporting it as changed.
+    Unchanged { leader: Option<H256>, order: Option<Vec<H256>> },
+    /// This level's leader changed to `leader` under the new vote set;
+    /// its previous contents are genuinely displaced.
+    Changed { level: usize, leader: H256, old_order: Option<Vec<H256>> },
+    /// This level (and, by construction, everything that had been
+    /// recorded above it) is no longer confirmed at all.
+    Gone { level: usize, old_order: Option<Vec<H256>> },
+}
+
+// decide, level by level starting at `from_level`, which of the
+// previously-confirmed levels in `unwound` (as returned by
+// `unwind_from`, in level order) were only unwound because a Vec can
+// only be truncated from the tail, versus which actually changed under
+// the new vote set. `recompute` — injected rather than calling
+// `proposer_leader` directly — is what would be the next level's leader
+// today; this indirection is what lets the decision logic here (in
+// particular, that an unaffected level is never reported as both
+// removed and re-added) be unit-tested without a real block DAG.
+fn plan_cascade(
+    unwound: Vec<(Option<H256>, Option<Vec<H256>>)>,
+    from_level: usize,
+    mut recompute: impl FnMut(usize) -> Option<H256>,
+) -> Vec<CascadeStep> {
+    let mut steps = Vec::with_capacity(unwound.len());
+    let mut stopped = false;
+    for (offset, (old_leader, old_order)) in unwound.into_iter().enumerate() {
+        let level = from_level + offset;
+        if stopped {
+            steps.push(CascadeStep::Gone { level, old_order });
+            continue;
+        }
+        let recomputed = recompute(level);
+        if recomputed == old_leader {
+            steps.push(CascadeStep::Unchanged { leader: old_leader, order: old_order });
+        } else if let Some(leader) = recomputed {
+            steps.push(CascadeStep::Changed { level, leader, old_order });
+        } else {
+            steps.push(CascadeStep::Gone { level, old_order });
+            stopped = true;
+        }
+    }
+    steps
+}
+
+// the structural checks on a snapshot that don't require the caller's
+// loaded blocks, split out so they can be exercised directly by tests.
+fn validate_snapshot(snapshot: &LedgerIndexSnapshot) -> Result<(), SnapshotError> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+    }
+    if snapshot.ledger_order.len() != snapshot.leader_sequence.len() {
+        return Err(SnapshotError::Inconsistent(
+            "ledger_order and leader_sequence have different lengths",
+        ));
+    }
+    if snapshot.finalized_height > snapshot.leader_sequence.len() {
+        return Err(SnapshotError::Inconsistent(
+            "finalized height exceeds the confirmed leader sequence",
+        ));
+    }
+    for (leader, order) in snapshot.leader_sequence.iter().zip(snapshot.ledger_order.iter()) {
+        if leader.is_none() && order.is_some() {
+            return Err(SnapshotError::Inconsistent(
+                "a level with no confirmed leader has a populated ledger order",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// bump this whenever the fields below change in a way that isn't
+// backward compatible, so an old on-disk snapshot is rejected instead of
+// silently misinterpreted.
+// v2 added `finalized_height`, so a v1 snapshot (which predates BFT
+// finality) is rejected rather than silently restored with its finality
+// floor reset to zero.
+const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct LedgerIndexSnapshot {
+    version: u32,
+    voter_tips: Vec<H256>,
+    proposer_tip: H256,
+    unconfirmed_proposer: Vec<H256>,
+    leader_sequence: Vec<Option<H256>>,
+    ledger_order: Vec<Option<Vec<H256>>>,
+    finalized_height: usize,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    UnsupportedVersion(u32),
+    Inconsistent(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported ledger index snapshot version {}", v)
+            }
+            SnapshotError::Inconsistent(msg) => write!(f, "inconsistent ledger index snapshot: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Identifies a finality authority. In practice this is the hash of the
+/// authority's public key; signature verification against that key is
+/// the caller's (networking layer's) responsibility before handing a
+/// precommit to `LedgerIndex::record_finality_precommit`.
+pub type AuthorityId = H256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Authority {
+    pub id: AuthorityId,
+    pub weight: u64,
+}
+
+/// The fixed authority set and weights used for BFT finality, stored
+/// alongside the rest of node configuration.
+#[derive(Clone, Debug)]
+pub struct FinalityConfig {
+    authorities: Vec<Authority>,
+}
+
+impl FinalityConfig {
+    pub fn new(authorities: Vec<Authority>) -> Self {
+        Self { authorities }
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.authorities.iter().map(|a| a.weight).sum()
+    }
+
+    fn weight_of(&self, id: AuthorityId) -> u64 {
+        self.authorities
+            .iter()
+            .find(|a| a.id == id)
+            .map_or(0, |a| a.weight)
+    }
+}
+
+// combine the confirmed leader hashes of levels 0..upto_level into a
+// single digest that authorities precommit to. order-sensitive, so a
+// reorg below `upto_level` necessarily changes the digest. Uses the same
+// sha256-based `hash_bytes` as the ledger trie, since this digest is
+// compared across validators and must be stable across builds. Returns
+// `None`, rather than panicking, if any level below `upto_level` isn't
+// confirmed yet: this is reachable with caller-chosen input (a level from
+// the precommit protocol), not just a programming error.
+fn leader_prefix_digest(leader_sequence: &[Option<H256>], upto_level: usize) -> Option<H256> {
+    if leader_sequence.len() < upto_level {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(upto_level * 32);
+    for leader in &leader_sequence[..upto_level] {
+        bytes.extend_from_slice((*leader)?.as_ref());
+    }
+    Some(hash_bytes(&bytes))
+}
+
+// Tendermint-style prevote/precommit tally over leader-prefix digests.
+// `LedgerIndex` only ever sees precommits, since the prevote step is a
+// purely networking-layer liveness optimization that doesn't change
+// which digest ultimately gets finalized.
+struct Finality {
+    config: FinalityConfig,
+    round: u64,
+    // digest -> authorities that precommitted it in the current round
+    precommits: HashMap<H256, HashSet<AuthorityId>>,
+    // authority -> the single digest it has precommitted in the current
+    // round. Caps each authority's weight at one digest per round, so an
+    // equivocating authority can't have its weight counted toward two
+    // conflicting precommit buckets at once.
+    voted: HashMap<AuthorityId, H256>,
+    finalized_height: usize,
+}
+
+impl Finality {
+    fn new(config: FinalityConfig) -> Self {
+        Self {
+            config,
+            round: 0,
+            precommits: HashMap::new(),
+            voted: HashMap::new(),
+            finalized_height: 0,
+        }
+    }
+
+    // rebuild a `Finality` from a persisted `finalized_height`, with no
+    // in-flight round state: a restarted node re-collects precommits for
+    // whatever round the network is currently on.
+    fn restore(config: FinalityConfig, finalized_height: usize) -> Self {
+        Self {
+            config,
+            round: 0,
+            precommits: HashMap::new(),
+            voted: HashMap::new(),
+            finalized_height,
+        }
+    }
+
+    fn finalized_height(&self) -> usize {
+        self.finalized_height
+    }
+
+    fn record_precommit(
+        &mut self,
+        round: u64,
+        level: usize,
+        authority: AuthorityId,
+        digest: H256,
+    ) -> bool {
+        if round < self.round {
+            return false; // stale round, the authority is behind
+        }
+        if round > self.round {
+            // a new round discards precommits collected for the old one
+            self.round = round;
+            self.precommits.clear();
+            self.voted.clear();
+        }
+
+        if let Some(prior_digest) = self.voted.get(&authority) {
+            if *prior_digest != digest {
+                log::error!(
+                    "consensus fault: authority {:?} equivocated in round {}, ignoring",
+                    authority, round
+                );
+            }
+            // either a repeat of its existing vote or an equivocation;
+            // either way its weight is already accounted for (or
+            // deliberately withheld), so there's nothing new to tally.
+            return false;
+        }
+        self.voted.insert(authority, digest);
+
+        let voters = self.precommits.entry(digest).or_insert_with(HashSet::new);
+        voters.insert(authority);
+        let weight: u64 = voters.iter().copied().map(|a| self.config.weight_of(a)).sum();
+
+        if weight * 3 > self.config.total_weight() * 2 && level + 1 > self.finalized_height {
+            self.finalized_height = level + 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// depth of the binary trie in bits: one bit per bit of a full H256 key,
+// so routing is a direct decomposition of the (cryptographic) hash
+// itself rather than a derived, lower-entropy digest of it.
+const TRIE_DEPTH: u32 = 256;
+
+// sha256 is used throughout this module for anything consensus-critical
+// (trie node hashes, leader-prefix digests, eligibility scores): unlike
+// `std::collections::hash_map::DefaultHasher`, it's deterministic across
+// builds, toolchains, and validators, which is a hard requirement once
+// its output is compared, hashed again, or voted on across the network.
+fn hash_bytes(bytes: &[u8]) -> H256 {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(bytes);
+    let digest = context.finish();
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(digest.as_ref());
+    H256::from(buf)
+}
+
+fn combine_hashes(left: H256, right: H256) -> H256 {
+    let mut bytes = Vec::with_capacity(64);
+    bytes.extend_from_slice(left.as_ref());
+    bytes.extend_from_slice(right.as_ref());
+    hash_bytes(&bytes)
+}
+
+fn leaf_hash(key: H256, position: u64) -> H256 {
+    let mut bytes = Vec::with_capacity(40);
+    bytes.extend_from_slice(key.as_ref());
+    bytes.extend_from_slice(&position.to_be_bytes());
+    hash_bytes(&bytes)
+}
+
+fn key_bit(key: H256, depth: u32) -> bool {
+    let bytes: &[u8] = key.as_ref();
+    let byte = bytes[(depth / 8) as usize];
+    let bit_in_byte = 7 - (depth % 8);
+    (byte >> bit_in_byte) & 1 == 1
+}
+
+#[derive(Clone)]
+enum TrieNode {
+    Empty,
+    Leaf { key: H256, position: u64, hash: H256 },
+    Branch {
+        hash: H256,
+        left: Box<TrieNode>,
+        right: Box<TrieNode>,
+    },
+}
+
+impl TrieNode {
+    fn hash(&self) -> H256 {
+        match self {
+            TrieNode::Empty => H256::default(),
+            TrieNode::Leaf { hash, .. } => *hash,
+            TrieNode::Branch { hash, .. } => *hash,
+        }
+    }
+
+    fn insert(self, key: H256, depth: u32, position: u64) -> TrieNode {
+        if depth == TRIE_DEPTH {
+            if let TrieNode::Leaf { key: existing_key, .. } = &self {
+                assert_eq!(
+                    *existing_key, key,
+                    "trie depth exhausted by two distinct keys, which would require a SHA-256 collision",
+                );
+            }
+            return TrieNode::Leaf {
+                key,
+                position,
+                hash: leaf_hash(key, position),
+            };
+        }
+        let (left, right) = match self {
+            TrieNode::Empty => (TrieNode::Empty, TrieNode::Empty),
+            TrieNode::Leaf { .. } => unreachable!("leaves only occur at depth == TRIE_DEPTH"),
+            TrieNode::Branch { left, right, .. } => (*left, *right),
+        };
+        let (left, right) = if key_bit(key, depth) {
+            (left, right.insert(key, depth + 1, position))
+        } else {
+            (left.insert(key, depth + 1, position), right)
+        };
+        TrieNode::Branch {
+            hash: combine_hashes(left.hash(), right.hash()),
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn remove(self, key: H256, depth: u32) -> TrieNode {
+        if depth == TRIE_DEPTH {
+            return match self {
+                // only remove the leaf that actually holds this key; a
+                // mismatch would mean the trie was corrupted, so leave it
+                // untouched rather than deleting the wrong entry.
+                TrieNode::Leaf { key: existing_key, .. } if existing_key == key => TrieNode::Empty,
+                other => other,
+            };
+        }
+        match self {
+            TrieNode::Empty => TrieNode::Empty,
+            TrieNode::Leaf { .. } => unreachable!("leaves only occur at depth == TRIE_DEPTH"),
+            TrieNode::Branch { left, right, .. } => {
+                let (left, right) = if key_bit(key, depth) {
+                    (*left, right.remove(key, depth + 1))
+                } else {
+                    (left.remove(key, depth + 1), *right)
+                };
+                if matches!(left, TrieNode::Empty) && matches!(right, TrieNode::Empty) {
+                    TrieNode::Empty
+                } else {
+                    TrieNode::Branch {
+                        hash: combine_hashes(left.hash(), right.hash()),
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    }
+                }
+            }
+        }
+    }
+
+    // returns the position the leaf for `key` was inserted at, so the
+    // caller can fold it into a `MerkleProof`. The leaf's own hash isn't
+    // returned: a proof must recompute it from the claimed key rather
+    // than trust it.
+    fn proof(
+        &self,
+        key: H256,
+        depth: u32,
+        path_bits: &mut Vec<bool>,
+        siblings: &mut Vec<H256>,
+    ) -> Option<u64> {
+        if depth == TRIE_DEPTH {
+            return match self {
+                TrieNode::Leaf { key: existing_key, position, .. } if *existing_key == key => {
+                    Some(*position)
+                }
+                _ => None,
+            };
+        }
+        match self {
+            TrieNode::Branch { left, right, .. } => {
+                let bit = key_bit(key, depth);
+                path_bits.push(bit);
+                if bit {
+                    siblings.push(left.hash());
+                    right.proof(key, depth + 1, path_bits, siblings)
+                } else {
+                    siblings.push(right.hash());
+                    left.proof(key, depth + 1, path_bits, siblings)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A sibling-hash inclusion path for one leaf of a `MerkleTrie`, from the
+/// leaf up to (but not including) the root.
+#[derive(Clone, Debug)]
+pub struct MerkleProof {
+    pub position: u64,
+    path_bits: Vec<bool>,
+    siblings: Vec<H256>,
+}
+
+impl MerkleProof {
+    /// Verify that `tx` is included (at `self.position`) under `root`.
+    /// The leaf hash is always recomputed from the caller-supplied `tx`,
+    /// never trusted from data embedded in the proof, so a proof
+    /// generated for one transaction can't be replayed to vouch for a
+    /// different one.
+    pub fn verify(&self, root: H256, tx: H256) -> bool {
+        let mut acc = leaf_hash(tx, self.position);
+        for (bit, sibling) in self.path_bits.iter().zip(self.siblings.iter()).rev() {
+            acc = if *bit {
+                combine_hashes(*sibling, acc)
+            } else {
+                combine_hashes(acc, *sibling)
+            };
+        }
+        acc == root
+    }
+}
+
+/// A binary trie over transaction-block hashes, committing to the
+/// confirmed ledger so a light client holding only `root()` can verify a
+/// transaction block's inclusion (and position) via a `MerkleProof`.
+#[derive(Clone)]
+struct MerkleTrie {
+    root: TrieNode,
+}
+
+impl MerkleTrie {
+    fn new() -> Self {
+        Self {
+            root: TrieNode::Empty,
+        }
+    }
+
+    fn root(&self) -> H256 {
+        self.root.hash()
+    }
+
+    fn insert(&mut self, tx: H256, position: u64) {
+        self.root = std::mem::replace(&mut self.root, TrieNode::Empty).insert(tx, 0, position);
+    }
+
+    fn remove(&mut self, tx: H256) {
+        self.root = std::mem::replace(&mut self.root, TrieNode::Empty).remove(tx, 0);
+    }
+
+    fn inclusion_proof(&self, tx: H256) -> Option<MerkleProof> {
+        let mut path_bits = Vec::new();
+        let mut siblings = Vec::new();
+        let position = self.root.proof(tx, 0, &mut path_bits, &mut siblings)?;
+        Some(MerkleProof {
+            position,
+            path_bits,
+            siblings,
+        })
+    }
+}
+
+/// A per-epoch-evolving stake commitment. A voter's weight for an epoch
+/// is derived from its committed key material and the epoch's nonce,
+/// modeled after an evolving-coin leader eligibility scheme: the nonce
+/// itself evolves every epoch as `hash(key_material || previous_nonce)`
+/// (see `evolve_epoch_nonce`), so weights rotate each epoch and can't be
+/// pre-ground by an adversary who doesn't yet know the upcoming nonce.
+#[derive(Clone, Copy, Debug)]
+pub struct EvolvingCoin {
+    committed_key: H256,
+    stake: u64,
+}
+
+impl EvolvingCoin {
+    pub fn new(committed_key: H256, stake: u64) -> Self {
+        Self { committed_key, stake }
+    }
+
+    fn weight_for_epoch(&self, epoch_nonce: H256) -> f32 {
+        let eligibility = combine_hashes(self.committed_key, epoch_nonce);
+        let lottery = eligibility_score(eligibility) % 100;
+        self.stake as f32 * lottery as f32 / 100.0
+    }
+}
+
+/// Evolve a per-epoch nonce forward: `hash(key_material || previous_nonce)`.
+/// The result isn't known until `previous_nonce` is, so coin weights for
+/// the new epoch can't be predicted ahead of time.
+pub fn evolve_epoch_nonce(key_material: H256, previous_nonce: H256) -> H256 {
+    combine_hashes(key_material, previous_nonce)
+}
+
+// `hash` is already the output of a cryptographic hash (see
+// `weight_for_epoch` above), so its leading bytes are themselves
+// uniformly distributed; hashing again with a non-cryptographic,
+// build-dependent hasher would only make the lottery non-reproducible
+// across validators without adding any real entropy.
+fn eligibility_score(hash: H256) -> u64 {
+    let bytes: &[u8] = hash.as_ref();
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+/// Selects between uniform per-chain confirmation and stake-weighted
+/// confirmation, so both can be benchmarked against the same block DAG.
+#[derive(Clone, Debug)]
+pub enum ConfirmationMode {
+    /// Every voter chain counts as one equal vote (the original policy).
+    Uniform,
+    /// Each voter chain's vote is weighted by its evolving stake for the
+    /// current epoch.
+    StakeWeighted {
+        coins: HashMap<u16, EvolvingCoin>,
+        epoch_nonce: H256,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsensusParams {
+    pub mode: ConfirmationMode,
+    pub quantile: f32,
+    pub adversary_ratio: f32,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            mode: ConfirmationMode::Uniform,
+            quantile: DEFAULT_QUANTILE,
+            adversary_ratio: DEFAULT_ADVERSARY_RATIO,
+        }
+    }
+}
+
+impl ConsensusParams {
+    pub fn uniform(quantile: f32, adversary_ratio: f32) -> Self {
+        Self { mode: ConfirmationMode::Uniform, quantile, adversary_ratio }
+    }
+
+    pub fn stake_weighted(
+        coins: HashMap<u16, EvolvingCoin>,
+        epoch_nonce: H256,
+        quantile: f32,
+        adversary_ratio: f32,
+    ) -> Self {
+        Self {
+            mode: ConfirmationMode::StakeWeighted { coins, epoch_nonce },
+            quantile,
+            adversary_ratio,
+        }
+    }
+
+    fn chain_weight(&self, chain_number: u16) -> f32 {
+        match &self.mode {
+            ConfirmationMode::Uniform => 1.0,
+            ConfirmationMode::StakeWeighted { coins, epoch_nonce } => coins
+                .get(&chain_number)
+                .map_or(0.0, |coin| coin.weight_for_epoch(*epoch_nonce)),
+        }
+    }
+
+    fn total_weight(&self, voter_tips: &[Voter]) -> f32 {
+        match &self.mode {
+            ConfirmationMode::Uniform => voter_tips.len() as f32,
+            ConfirmationMode::StakeWeighted { .. } => voter_tips
+                .iter()
+                .map(|v| self.chain_weight(v.chain_number))
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod evolving_coin_tests {
+    use super::*;
+
+    #[test]
+    fn weight_for_epoch_is_deterministic() {
+        let coin = EvolvingCoin::new(H256::from([7; 32]), 1_000);
+        let nonce = H256::from([9; 32]);
+        assert_eq!(coin.weight_for_epoch(nonce), coin.weight_for_epoch(nonce));
+    }
+
+    #[test]
+    fn weight_for_epoch_never_exceeds_stake() {
+        let coin = EvolvingCoin::new(H256::from([1; 32]), 500);
+        for byte in 0..8u8 {
+            let weight = coin.weight_for_epoch(H256::from([byte; 32]));
+            assert!(weight >= 0.0 && weight <= 500.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod finality_tests {
+    use super::*;
+
+    fn authority(byte: u8, weight: u64) -> Authority {
+        Authority { id: H256::from([byte; 32]), weight }
+    }
+
+    fn digest(byte: u8) -> H256 {
+        H256::from([byte; 32])
+    }
+
+    #[test]
+    fn finalizes_once_quorum_exceeds_two_thirds() {
+        let config = FinalityConfig::new(vec![
+            authority(1, 1),
+            authority(2, 1),
+            authority(3, 1),
+        ]);
+        let mut finality = Finality::new(config);
+
+        assert!(!finality.record_precommit(0, 4, digest(1), digest(0xaa)));
+        assert!(!finality.record_precommit(0, 4, digest(2), digest(0xaa)));
+        // 2 of 3 equal-weight authorities is exactly 2/3, not more than
+        // 2/3, so finality shouldn't trigger until the third joins.
+        assert_eq!(finality.finalized_height(), 0);
+        assert!(finality.record_precommit(0, 4, digest(3), digest(0xaa)));
+        assert_eq!(finality.finalized_height(), 5);
+    }
+
+    #[test]
+    fn equivocating_authority_does_not_count_twice() {
+        // authority 1 holds half of the total weight of 4, so its digest
+        // plus one more authority is a quorum of more than 2/3, and its
+        // weight counted toward a second digest would be one too.
+        let config = FinalityConfig::new(vec![
+            authority(1, 2),
+            authority(2, 1),
+            authority(3, 1),
+        ]);
+        let mut finality = Finality::new(config);
+
+        assert!(!finality.record_precommit(0, 4, digest(1), digest(0xaa)));
+        // authority 1 equivocates: precommits a second, conflicting
+        // digest in the same round. it must not also count toward this
+        // new bucket.
+        assert!(!finality.record_precommit(0, 4, digest(1), digest(0xbb)));
+        assert!(!finality.record_precommit(0, 4, digest(2), digest(0xbb)));
+        assert_eq!(finality.finalized_height(), 0);
+
+        // even the honest majority precommitting the equivocator's first
+        // digest can still finalize it, since that vote stands.
+        assert!(finality.record_precommit(0, 4, digest(3), digest(0xaa)));
+        assert_eq!(finality.finalized_height(), 5);
+    }
+}
+
+#[cfg(test)]
+mod snapshot_validation_tests {
+    use super::*;
+
+    fn base_snapshot() -> LedgerIndexSnapshot {
+        LedgerIndexSnapshot {
+            version: SNAPSHOT_VERSION,
+            voter_tips: Vec::new(),
+            proposer_tip: H256::default(),
+            unconfirmed_proposer: Vec::new(),
+            leader_sequence: vec![Some(H256::from([1; 32])), None],
+            ledger_order: vec![Some(vec![H256::from([2; 32])]), None],
+            finalized_height: 1,
+        }
+    }
+
+    #[test]
+    fn accepts_a_well_formed_snapshot() {
+        assert!(validate_snapshot(&base_snapshot()).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut snapshot = base_snapshot();
+        snapshot.version = SNAPSHOT_VERSION - 1;
+        assert!(matches!(
+            validate_snapshot(&snapshot),
+            Err(SnapshotError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_lengths() {
+        let mut snapshot = base_snapshot();
+        snapshot.ledger_order.push(None);
+        assert!(matches!(
+            validate_snapshot(&snapshot),
+            Err(SnapshotError::Inconsistent(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_finalized_height_past_the_leader_sequence() {
+        let mut snapshot = base_snapshot();
+        snapshot.finalized_height = snapshot.leader_sequence.len() + 1;
+        assert!(matches!(
+            validate_snapshot(&snapshot),
+            Err(SnapshotError::Inconsistent(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_ledger_order_on_an_unconfirmed_level() {
+        let mut snapshot = base_snapshot();
+        snapshot.leader_sequence[1] = None;
+        snapshot.ledger_order[1] = Some(vec![H256::from([3; 32])]);
+        assert!(matches!(
+            validate_snapshot(&snapshot),
+            Err(SnapshotError::Inconsistent(_))
+        ));
+    }
+}
+
+// `from_snapshot`'s proposer_tip/voter_tips hash checks can't be
+// exercised without real `crate::chain` blocks, and this source tree has
+// no `crate::chain` module at all to build them against. `rebuild_ledger_trie`
+// is the rest of the round trip — the part that actually reconstructs
+// state from `ledger_order` rather than just comparing hashes — so it's
+// covered directly here, independent of `from_snapshot`'s signature.
+#[cfg(test)]
+mod snapshot_round_trip_tests {
+    use super::*;
+
+    fn h(byte: u8) -> H256 {
+        H256::from([byte; 32])
+    }
+
+    #[test]
+    fn rebuild_matches_incremental_insertion() {
+        let ledger_order = vec![Some(vec![h(1), h(2)]), None, Some(vec![h(3)])];
+        let (trie, confirmed, next_position) = rebuild_ledger_trie(&ledger_order);
+
+        let mut expected_trie = MerkleTrie::new();
+        expected_trie.insert(h(1), 0);
+        expected_trie.insert(h(2), 1);
+        expected_trie.insert(h(3), 2);
+
+        assert_eq!(trie.root(), expected_trie.root());
+        assert_eq!(confirmed, HashSet::from_iter(vec![h(1), h(2), h(3)]));
+        assert_eq!(next_position, 3);
+    }
+
+    #[test]
+    fn rebuild_is_empty_for_an_empty_ledger_order() {
+        let (trie, confirmed, next_position) = rebuild_ledger_trie(&[]);
+        assert_eq!(trie.root(), MerkleTrie::new().root());
+        assert!(confirmed.is_empty());
+        assert_eq!(next_position, 0);
+    }
+
+    #[test]
+    fn snapshot_fields_round_trip_through_rebuild_and_finality_restore() {
+        // exercises the part of to_snapshot/from_snapshot that doesn't
+        // require real Proposer/Voter blocks: the ledger_order -> trie /
+        // confirmed_transactions rebuild, and restoring Finality's
+        // persisted floor.
+        let snapshot = LedgerIndexSnapshot {
+            version: SNAPSHOT_VERSION,
+            voter_tips: Vec::new(),
+            proposer_tip: H256::default(),
+            unconfirmed_proposer: Vec::new(),
+            leader_sequence: vec![Some(h(0xA)), Some(h(0xB))],
+            ledger_order: vec![Some(vec![h(1)]), Some(vec![h(2), h(3)])],
+            finalized_height: 1,
+        };
+        validate_snapshot(&snapshot).expect("well-formed snapshot");
+
+        let (trie, confirmed, next_position) = rebuild_ledger_trie(&snapshot.ledger_order);
+        assert_eq!(confirmed.len(), 3);
+        assert_eq!(next_position, 3);
+        assert!(trie.inclusion_proof(h(2)).is_some());
+
+        let finality = Finality::restore(
+            FinalityConfig::new(vec![Authority { id: h(1), weight: 1 }]),
+            snapshot.finalized_height,
+        );
+        assert_eq!(finality.finalized_height(), snapshot.finalized_height);
+    }
+}
+
+#[cfg(test)]
+mod merkle_trie_tests {
+    use super::*;
+
+    fn h(byte: u8) -> H256 {
+        H256::from([byte; 32])
+    }
+
+    #[test]
+    fn inclusion_proof_round_trip() {
+        let mut trie = MerkleTrie::new();
+        trie.insert(h(1), 0);
+        trie.insert(h(2), 1);
+        trie.insert(h(3), 2);
+
+        let proof = trie.inclusion_proof(h(2)).expect("h(2) was inserted");
+        assert_eq!(proof.position, 1);
+        assert!(proof.verify(trie.root(), h(2)));
+    }
+
+    #[test]
+    fn proof_rejects_wrong_key() {
+        let mut trie = MerkleTrie::new();
+        trie.insert(h(1), 0);
+        trie.insert(h(2), 1);
+
+        let proof = trie.inclusion_proof(h(2)).unwrap();
+        // a proof generated for h(2) must not also verify for h(3), even
+        // though it's never been inserted at all.
+        assert!(!proof.verify(trie.root(), h(3)));
+    }
+
+    #[test]
+    fn remove_drops_from_proof_and_changes_root() {
+        let mut trie = MerkleTrie::new();
+        trie.insert(h(1), 0);
+        trie.insert(h(2), 1);
+        let root_with_two = trie.root();
+
+        trie.remove(h(2));
+        assert!(trie.inclusion_proof(h(2)).is_none());
+        assert_ne!(trie.root(), root_with_two);
+
+        // removing a key that was never inserted is a no-op
+        let root_after_remove = trie.root();
+        trie.remove(h(9));
+        assert_eq!(trie.root(), root_after_remove);
+    }
+}
+
+// `advance_ledger_to` itself can't be driven end to end without real
+// `Proposer`/`Voter` blocks to run leader election over, and this source
+// tree has no `crate::chain` module at all (no file, no `mod`
+// declaration) to build minimal fixtures against. `plan_cascade` is the
+// part of the reorg path that actually decides which levels come back as
+// added/removed, including whether an unaffected level would be reported
+// as both removed and re-added, so it's
+// factored out to take the "what would this level's leader be now"
+// lookup as a plain closure, which makes it fully testable here with
+// synthetic data instead of a real block DAG.
+#[cfg(test)]
+mod reorg_cascade_tests {
+    use super::*;
+
+    fn h(byte: u8) -> H256 {
+        H256::from([byte; 32])
+    }
+
+    #[test]
+    fn unaffected_level_is_restored_without_touching_added_or_removed() {
+        // level 0 had leader A (one tx), level 1 had leader B (one tx).
+        let unwound = vec![
+            (Some(h(0xA0)), Some(vec![h(0x01)])),
+            (Some(h(0xB0)), Some(vec![h(0x02)])),
+        ];
+        // under the new vote set level 0's leader changes to C, but
+        // level 1 recomputes to the exact same leader it already had.
+        let steps = plan_cascade(unwound, 0, |level| match level {
+            0 => Some(h(0xC0)),
+            1 => Some(h(0xB0)),
+            _ => None,
+        });
+
+        assert_eq!(steps.len(), 2);
+        assert!(matches!(
+            &steps[0],
+            CascadeStep::Changed { level: 0, leader, .. } if *leader == h(0xC0)
+        ));
+        assert!(matches!(
+            &steps[1],
+            CascadeStep::Unchanged { leader: Some(leader), .. } if *leader == h(0xB0)
+        ));
+    }
+
+    #[test]
+    fn cascade_stops_reporting_changes_once_a_level_stops_differing() {
+        let unwound = vec![
+            (Some(h(0xA0)), Some(vec![h(0x01)])),
+            (Some(h(0xB0)), Some(vec![h(0x02)])),
+            (Some(h(0xC0)), Some(vec![h(0x03)])),
+        ];
+        // level 0 changes; level 1 recomputes unchanged; level 2 would
+        // also recompute unchanged, so nothing above level 0 should be
+        // reported as touched at all.
+        let steps = plan_cascade(unwound, 0, |level| match level {
+            0 => Some(h(0xD0)),
+            1 => Some(h(0xB0)),
+            2 => Some(h(0xC0)),
+            _ => None,
+        });
+
+        assert!(matches!(steps[0], CascadeStep::Changed { level: 0, .. }));
+        assert!(matches!(steps[1], CascadeStep::Unchanged { .. }));
+        assert!(matches!(steps[2], CascadeStep::Unchanged { .. }));
+    }
+
+    #[test]
+    fn a_level_becoming_wholly_unconfirmed_takes_everything_above_it_with_it() {
+        let unwound = vec![
+            (Some(h(0xA0)), Some(vec![h(0x01)])),
+            (Some(h(0xB0)), Some(vec![h(0x02)])),
+            (Some(h(0xC0)), Some(vec![h(0x03)])),
+        ];
+        // level 0 changes, level 1 no longer has any confirmable leader
+        // under the new vote set; level 2 must come back as `Gone` too,
+        // even though its own recomputed leader (deliberately set to its
+        // old value here) never actually gets consulted, since nothing
+        // can stay confirmed on top of an unconfirmed level.
+        let steps = plan_cascade(unwound, 0, |level| match level {
+            0 => Some(h(0xD0)),
+            1 => None,
+            _ => Some(h(0xC0)),
+        });
+
+        assert_eq!(steps.len(), 3);
+        assert!(matches!(steps[0], CascadeStep::Changed { level: 0, .. }));
+        assert!(matches!(steps[1], CascadeStep::Gone { level: 1, .. }));
+        assert!(matches!(steps[2], CascadeStep::Gone { level: 2, .. }));
+    }
 }
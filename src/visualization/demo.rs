@@ -1,15 +1,18 @@
 use crate::block::{Block, Content};
 use crate::crypto::hash::{Hashable, H256};
 
+use std::collections::{HashSet, VecDeque};
 use std::convert::From;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use log::warn;
+use std::time::Duration;
 
-use std::sync::mpsc;
+use log::warn;
 use websocket::client::ClientBuilder;
 use websocket::message::OwnedMessage;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ProposerBlock {
     /// Hash of this block
     id: String,
@@ -20,7 +23,7 @@ struct ProposerBlock {
     /// Proposer refs
     proposer_refs: Vec<String>,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct VoterBlock {
     /// Hash of this block
     id: String,
@@ -33,26 +36,47 @@ struct VoterBlock {
     /// Votes
     votes: Vec<String>,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct TransactionBlock {
     /// Hash of this block
     id: String,
     /// Proposer parent
     parent: String,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct UpdatedLedger {
-    /// Hash of proposer blocks that are added to ledger 
+    /// Hash of proposer blocks that are added to ledger
     added: Vec<String>,
-    /// Hash of proposer blocks that are removed from ledger 
+    /// Hash of proposer blocks that are removed from ledger
     removed: Vec<String>,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 enum DemoMsg {
     ProposerBlock(ProposerBlock),
     VoterBlock(VoterBlock),
     TransactionBlock(TransactionBlock),
     UpdatedLedger(UpdatedLedger),
+    /// A proposer block was newly confirmed as the leader of `level`.
+    LeaderElected { level: u64, leader: String },
+    /// `level`'s previously confirmed leader was rolled back by a reorg.
+    LevelDeconfirmed { level: u64 },
+    /// The root of the confirmed-ledger Merkle trie, for monitors to
+    /// cross-check against their own view of node state.
+    LedgerRoot { root: String },
+}
+
+impl DemoMsg {
+    fn kind(&self) -> EventKind {
+        match self {
+            DemoMsg::ProposerBlock(_) => EventKind::ProposerBlock,
+            DemoMsg::VoterBlock(_) => EventKind::VoterBlock,
+            DemoMsg::TransactionBlock(_) => EventKind::TransactionBlock,
+            DemoMsg::UpdatedLedger(_) => EventKind::UpdatedLedger,
+            DemoMsg::LeaderElected { .. } => EventKind::LeaderElected,
+            DemoMsg::LevelDeconfirmed { .. } => EventKind::LevelDeconfirmed,
+            DemoMsg::LedgerRoot { .. } => EventKind::LedgerRoot,
+        }
+    }
 }
 
 impl From<&Block> for DemoMsg {
@@ -76,42 +100,268 @@ impl From<&Block> for DemoMsg {
     }
 }
 
-pub fn new(url: &str) -> mpsc::Sender<String> {
-    let (sender, receiver) = mpsc::channel();
-    let client_builder = ClientBuilder::new(url);
-    if let Ok(client_builder) = client_builder {
-        let client = client_builder
-            .add_protocol("rust-websocket")
-            .connect_insecure();
-        if let Ok(mut client) = client {
-            thread::spawn(move|| {
-                for msg in receiver.iter() {
-                    if client.send_message(&OwnedMessage::Text(msg)).is_err() {break;}
+/// The kinds of event a sink can subscribe to, so it only receives (and
+/// is only queued) the subset it cares about.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum EventKind {
+    ProposerBlock,
+    VoterBlock,
+    TransactionBlock,
+    UpdatedLedger,
+    LeaderElected,
+    LevelDeconfirmed,
+    LedgerRoot,
+}
+
+/// An event as assigned a place in the dispatcher's monotonic sequence,
+/// so a reconnecting sink can ask to be replayed everything since the
+/// last sequence number it saw.
+#[derive(Serialize, Clone)]
+pub struct DemoEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    msg: DemoMsg,
+}
+
+/// Where a sink delivers events to.
+pub enum SinkEndpoint {
+    WebSocket(String),
+    Webhook(String),
+}
+
+const SINK_QUEUE_CAPACITY: usize = 256;
+const REPLAY_BUFFER_CAPACITY: usize = 4096;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct Sink {
+    filter: HashSet<EventKind>,
+    queue: mpsc::SyncSender<DemoEvent>,
+}
+
+/// Event-dispatch subsystem for the visualization demo.
+///
+/// Multiple sinks (websocket connections and HTTP webhooks) subscribe to
+/// a filtered subset of event kinds. Each sink has its own bounded queue
+/// so a slow or disconnected consumer can't stall block processing
+/// upstream; events are dropped from that sink's queue (not from the
+/// dispatcher) once it's full. Every dispatched event is kept in a
+/// bounded replay buffer so a reconnecting sink can be brought back up
+/// to date instead of silently missing the gap.
+pub struct EventDispatcher {
+    next_seq: AtomicU64,
+    replay_buffer: Mutex<VecDeque<DemoEvent>>,
+    sinks: Mutex<Vec<Sink>>,
+}
+
+impl EventDispatcher {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            next_seq: AtomicU64::new(0),
+            replay_buffer: Mutex::new(VecDeque::with_capacity(REPLAY_BUFFER_CAPACITY)),
+            sinks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Subscribe a new sink, filtered to `kinds`, replayed from
+    /// `since_seq` onward (pass 0 for a brand new sink). The sink
+    /// connects, and reconnects with exponential backoff, on its own
+    /// thread.
+    pub fn subscribe(self: &Arc<Self>, endpoint: SinkEndpoint, kinds: &[EventKind], since_seq: u64) {
+        let (sender, receiver) = mpsc::sync_channel(SINK_QUEUE_CAPACITY);
+        let filter: HashSet<EventKind> = kinds.iter().copied().collect();
+
+        for event in self.replay_since(since_seq, &filter) {
+            // best effort: if the queue is already full the backlog is
+            // dropped, live events still follow.
+            let _ = sender.try_send(event);
+        }
+
+        self.sinks.lock().unwrap().push(Sink { filter: filter.clone(), queue: sender });
+        let dispatcher = Arc::clone(self);
+        thread::spawn(move || run_sink(dispatcher, endpoint, filter, receiver));
+    }
+
+    /// Every buffered event of a kind in `filter`, from `since_seq`
+    /// onward. Used both for a sink's initial backfill and to re-fill
+    /// whatever gap a sink's bounded queue dropped while it was
+    /// disconnected.
+    fn replay_since(&self, since_seq: u64, filter: &HashSet<EventKind>) -> Vec<DemoEvent> {
+        self.replay_buffer
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.seq >= since_seq && filter.contains(&e.msg.kind()))
+            .cloned()
+            .collect()
+    }
+
+    fn dispatch(&self, msg: DemoMsg) {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let event = DemoEvent { seq, msg };
+
+        {
+            let mut replay = self.replay_buffer.lock().unwrap();
+            if replay.len() == REPLAY_BUFFER_CAPACITY {
+                replay.pop_front();
+            }
+            replay.push_back(event.clone());
+        }
+
+        let kind = event.msg.kind();
+        self.sinks.lock().unwrap().retain(|sink| {
+            if !sink.filter.contains(&kind) {
+                return true;
+            }
+            match sink.queue.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    warn!("demo event sink queue full, dropping event {}", event.seq);
+                    true
                 }
-            });
-        } else {
-            warn!("Fail to connect to demo websocket {}.", url);
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    pub fn insert_block(&self, block: &Block) {
+        self.dispatch(block.into());
+    }
+
+    pub fn update_ledger(&self, added: &[H256], removed: &[H256]) {
+        if added.is_empty() && removed.is_empty() {
+            return;
         }
-    } else {
-        warn!("Fail to connect to demo websocket {}.", url);
+        let added = added.iter().map(|x| x.to_string()).collect();
+        let removed = removed.iter().map(|x| x.to_string()).collect();
+        self.dispatch(DemoMsg::UpdatedLedger(UpdatedLedger { added, removed }));
+    }
+
+    pub fn leader_elected(&self, level: u64, leader: H256) {
+        self.dispatch(DemoMsg::LeaderElected { level, leader: leader.to_string() });
+    }
+
+    pub fn level_deconfirmed(&self, level: u64) {
+        self.dispatch(DemoMsg::LevelDeconfirmed { level });
+    }
+
+    pub fn ledger_root(&self, root: H256) {
+        self.dispatch(DemoMsg::LedgerRoot { root: root.to_string() });
+    }
+}
+
+fn run_sink(
+    dispatcher: Arc<EventDispatcher>,
+    endpoint: SinkEndpoint,
+    filter: HashSet<EventKind>,
+    receiver: mpsc::Receiver<DemoEvent>,
+) {
+    match endpoint {
+        SinkEndpoint::WebSocket(url) => run_websocket_sink(&dispatcher, &url, &filter, &receiver),
+        SinkEndpoint::Webhook(url) => run_webhook_sink(&dispatcher, &url, &filter, &receiver),
     }
-    sender
 }
 
-pub fn insert_block_msg(block: &Block) -> String {
-    let msg: DemoMsg = block.into();
-    let json: String = serde_json::to_string_pretty(&msg).unwrap();
-    json
+fn run_websocket_sink(
+    dispatcher: &EventDispatcher,
+    url: &str,
+    filter: &HashSet<EventKind>,
+    receiver: &mpsc::Receiver<DemoEvent>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    // the highest seq already delivered to this client, so a reconnect
+    // can backfill exactly the gap this sink's bounded queue dropped
+    // while disconnected, and a duplicate of an already-replayed event
+    // still sitting in the live queue is skipped rather than resent.
+    let mut last_delivered_seq: Option<u64> = None;
+    'reconnect: loop {
+        let client_builder = ClientBuilder::new(url).ok();
+        let client = client_builder.and_then(|b| b.add_protocol("rust-websocket").connect_insecure().ok());
+        let mut client = match client {
+            Some(client) => client,
+            None => {
+                warn!("fail to connect to demo websocket sink {}, retrying in {:?}", url, backoff);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        backoff = INITIAL_BACKOFF;
+
+        if let Some(since_seq) = last_delivered_seq {
+            for event in dispatcher.replay_since(since_seq + 1, filter) {
+                let json = serde_json::to_string_pretty(&event).unwrap();
+                if client.send_message(&OwnedMessage::Text(json)).is_err() {
+                    warn!("demo websocket sink {} disconnected, reconnecting", url);
+                    continue 'reconnect;
+                }
+                last_delivered_seq = Some(event.seq);
+            }
+        }
+
+        loop {
+            match receiver.recv() {
+                Ok(event) => {
+                    if last_delivered_seq.map_or(false, |seq| event.seq <= seq) {
+                        continue; // already delivered by the replay above
+                    }
+                    let json = serde_json::to_string_pretty(&event).unwrap();
+                    if client.send_message(&OwnedMessage::Text(json)).is_err() {
+                        warn!("demo websocket sink {} disconnected, reconnecting", url);
+                        continue 'reconnect;
+                    }
+                    last_delivered_seq = Some(event.seq);
+                }
+                // the dispatcher was dropped, nothing left to deliver
+                Err(_) => break 'reconnect,
+            }
+        }
+    }
 }
 
-pub fn update_ledger_msg(added: &[H256], removed: &[H256]) -> String {
-    if added.is_empty() && removed.is_empty() {
-        return String::from("");
+fn run_webhook_sink(
+    dispatcher: &EventDispatcher,
+    url: &str,
+    filter: &HashSet<EventKind>,
+    receiver: &mpsc::Receiver<DemoEvent>,
+) {
+    let client = reqwest::blocking::Client::new();
+    let mut last_delivered_seq: Option<u64> = None;
+
+    loop {
+        if let Some(since_seq) = last_delivered_seq {
+            for event in dispatcher.replay_since(since_seq + 1, filter) {
+                post_with_retry(&client, url, &event);
+                last_delivered_seq = Some(event.seq);
+            }
+        }
+
+        let event = match receiver.recv() {
+            Ok(event) => event,
+            // the dispatcher was dropped, nothing left to deliver
+            Err(_) => break,
+        };
+        if last_delivered_seq.map_or(false, |seq| event.seq <= seq) {
+            continue; // already delivered by the replay above
+        }
+        post_with_retry(&client, url, &event);
+        last_delivered_seq = Some(event.seq);
     }
-    let added = added.iter().map(|x|x.to_string()).collect();
-    let removed = removed.iter().map(|x|x.to_string()).collect();
-    let msg: DemoMsg = DemoMsg::UpdatedLedger(UpdatedLedger{added, removed});
-    let json: String = serde_json::to_string_pretty(&msg).unwrap();
-    json
 }
 
+fn post_with_retry(client: &reqwest::blocking::Client, url: &str, event: &DemoEvent) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match client.post(url).json(event).send() {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => {
+                warn!("demo webhook sink {} returned {}, retrying in {:?}", url, resp.status(), backoff);
+            }
+            Err(e) => {
+                warn!("demo webhook sink {} unreachable ({}), retrying in {:?}", url, e, backoff);
+            }
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}